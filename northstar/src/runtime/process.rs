@@ -18,9 +18,14 @@ use nix::{
     sys::{signal, wait},
     unistd,
 };
-use std::fmt::Debug;
+use std::{
+    convert::TryFrom,
+    fmt::{self, Debug},
+    os::unix::io::{AsRawFd, RawFd},
+    time::Duration,
+};
 use thiserror::Error;
-use tokio::{sync::mpsc, task};
+use tokio::{io::unix::AsyncFd, sync::mpsc, task, time};
 use wait::WaitStatus;
 
 pub(crate) const ENV_NAME: &str = "NAME";
@@ -33,8 +38,30 @@ pub type Pid = u32;
 pub enum ExitStatus {
     /// Process exited with exit code
     Exit(ExitCode),
-    /// Process was terminated by a signal
-    Signaled(signal::Signal),
+    /// Process was terminated by a signal. `core_dumped` reflects whether the
+    /// kernel also wrote a core file for the process (`WCOREDUMP`).
+    Signaled {
+        signal: signal::Signal,
+        core_dumped: bool,
+    },
+}
+
+impl ExitStatus {
+    /// Whether the process terminated cleanly, i.e. exited with code 0.
+    pub fn is_success(&self) -> bool {
+        matches!(self, ExitStatus::Exit(0))
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExitStatus::Exit(code) => write!(f, "{}", code),
+            ExitStatus::Signaled { signal, .. } => {
+                write!(f, "{} ({})", signal.as_str(), *signal as i32)
+            }
+        }
+    }
 }
 
 pub(crate) type ExitHandleWait = mpsc::Receiver<ExitStatus>;
@@ -60,65 +87,335 @@ pub enum Error {
     Os(String, nix::Error),
 }
 
-/// Spawn a task that waits for the process to exit. Once the process is exited send the return code
-// (if any) to the exit_tx handle passed
+/// Map a `WaitStatus` to an `ExitStatus` if it represents a terminal state of the
+/// process (exited or killed by a signal). All other states (stopped, ptrace
+/// events, continued, still alive) are transient and are reported as `None` so
+/// that callers know to keep waiting.
+fn exit_status(status: WaitStatus) -> Option<ExitStatus> {
+    match status {
+        // The process exited normally (as with exit() or returning from main) with the given exit code.
+        // This case matches the C macro WIFEXITED(status); the second field is WEXITSTATUS(status).
+        WaitStatus::Exited(_pid, code) => Some(ExitStatus::Exit(code)),
+
+        // The process was killed by the given signal.
+        // The third field indicates whether the signal generated a core dump. This case matches the C macro WIFSIGNALED(status); the last two fields correspond to WTERMSIG(status) and WCOREDUMP(status).
+        WaitStatus::Signaled(_pid, signal, core_dumped) => Some(ExitStatus::Signaled {
+            signal,
+            core_dumped,
+        }),
+
+        // The process is alive, but was stopped by the given signal.
+        // This is only reported if WaitPidFlag::WUNTRACED was passed. This case matches the C macro WIFSTOPPED(status); the second field is WSTOPSIG(status).
+        WaitStatus::Stopped(_pid, _signal) => None,
+
+        // The traced process was stopped by a PTRACE_EVENT_* event.
+        // See nix::sys::ptrace and ptrace(2) for more information. All currently-defined events use SIGTRAP as the signal; the third field is the PTRACE_EVENT_* value of the event.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        WaitStatus::PtraceEvent(_pid, _signal, _) => None,
+
+        // The traced process was stopped by execution of a system call, and PTRACE_O_TRACESYSGOOD is in effect.
+        // See ptrace(2) for more information.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        WaitStatus::PtraceSyscall(_pid) => None,
+
+        // The process was previously stopped but has resumed execution after receiving a SIGCONT signal.
+        // This is only reported if WaitPidFlag::WCONTINUED was passed. This case matches the C macro WIFCONTINUED(status).
+        WaitStatus::Continued(_pid) => None,
+
+        // There are currently no state changes to report in any awaited child process.
+        // This is only returned if WaitPidFlag::WNOHANG was used (otherwise wait() or waitpid() would block until there was something to report).
+        WaitStatus::StillAlive => None,
+    }
+}
+
+/// Wait for the process to exit and report its `ExitStatus` on `exit_handle` and
+/// `event_handle`. On Linux this reaps the child through a pidfd registered with
+/// the async reactor rather than parking a dedicated thread per container; see
+/// `wait_pidfd` for details and `wait_child` for the conditions under which it
+/// falls back to `waitpid_blocking`.
+///
+/// This is the only function that ever reaps `pid`: it is spawned once per
+/// container for its whole lifetime, and `stop` drives a graceful shutdown by
+/// signalling `pid` and waiting for this function's `exit_handle`
+/// notification instead of reaping `pid` itself, so there is only ever one
+/// `waitpid(2)`/`waitid(2)` caller for a given pid.
 pub(crate) async fn waitpid(
     name: &str,
     pid: u32,
     exit_handle: ExitHandleSignal,
     event_handle: EventTx,
 ) {
-    let name = name.to_string();
+    let status = wait_child(name, pid).await;
+
+    // Send notification to exit handle
+    exit_handle.send(status.clone()).await.ok();
+
+    // Send notification to main loop
+    event_handle
+        .send(Event::Exit(name.to_string(), status))
+        .await
+        .expect("Internal channel error on main event handle");
+}
+
+#[cfg(target_os = "linux")]
+async fn wait_child(name: &str, pid: u32) -> ExitStatus {
+    match pidfd_open(pid) {
+        Ok(fd) => match wait_pidfd(pid, fd).await {
+            Ok(status) => return status,
+            Err(e) => debug!(
+                "pidfd based reaping of {} ({}) failed ({}), falling back to waitpid",
+                name, pid, e
+            ),
+        },
+        Err(nix::Error::Sys(nix::errno::Errno::ENOSYS)) => debug!(
+            "pidfd_open is not supported by this kernel, falling back to waitpid for {} ({})",
+            name, pid
+        ),
+        Err(e) => debug!(
+            "pidfd_open failed for {} ({}) ({}), falling back to waitpid",
+            name, pid, e
+        ),
+    }
+    waitpid_blocking(pid).await
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn wait_child(_name: &str, pid: u32) -> ExitStatus {
+    waitpid_blocking(pid).await
+}
+
+/// An owned pidfd: closes the underlying descriptor on drop. `AsyncFd` does
+/// not close the fd it wraps itself, it relies on the wrapped type's `Drop`
+/// to do so, so a bare `RawFd` would leak here.
+#[cfg(target_os = "linux")]
+struct PidFd(RawFd);
+
+#[cfg(target_os = "linux")]
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        // SAFETY: self.0 is a valid fd owned exclusively by this struct.
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Open a process file descriptor for `pid` via `pidfd_open(2)`. `nix` does not
+/// wrap this syscall, so it's issued directly through `libc`.
+#[cfg(target_os = "linux")]
+fn pidfd_open(pid: u32) -> nix::Result<PidFd> {
+    // SAFETY: pidfd_open(2) takes a pid and a flags word (0, as no flags are
+    // currently defined beyond PIDFD_NONBLOCK which we don't need) and returns
+    // either a valid fd or -1/errno; no pointers are involved.
+    let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+    if fd == -1 {
+        Err(nix::Error::Sys(nix::errno::Errno::last()))
+    } else {
+        Ok(PidFd(fd as RawFd))
+    }
+}
+
+/// Await exit of the process behind `pidfd` and reap it with `waitid(P_PIDFD)`.
+/// The fd becomes readable once the process has terminated, which sidesteps
+/// both the thread-per-container cost of `waitpid_blocking` and the PID-reuse
+/// race inherent to waiting on a bare `Pid`.
+#[cfg(target_os = "linux")]
+async fn wait_pidfd(pid: u32, fd: PidFd) -> Result<ExitStatus, Error> {
+    // Keep the raw value around for the waitid(2) call below; `fd` itself is
+    // moved into `async_fd`, which owns it (and closes it on drop) from here on.
+    let raw_fd = fd.as_raw_fd();
+    let async_fd = AsyncFd::new(fd)
+        .map_err(|e| Error::Io(format!("Failed to register pidfd for {}", pid), e))?;
+
+    loop {
+        let mut guard = async_fd
+            .readable()
+            .await
+            .map_err(|e| Error::Io(format!("Failed to poll pidfd for {}", pid), e))?;
+
+        // SAFETY: siginfo_t is plain data and waitid(2) fully populates it on
+        // success. The pidfd only reports readable once the process has
+        // exited, so this WEXITED wait on it does not block.
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let result = unsafe {
+            libc::waitid(
+                libc::P_PIDFD,
+                raw_fd as libc::id_t,
+                &mut info,
+                libc::WEXITED,
+            )
+        };
+
+        if result == -1 {
+            let errno = nix::errno::Errno::last();
+            if errno == nix::errno::Errno::EAGAIN {
+                guard.clear_ready();
+                continue;
+            }
+            return Err(Error::Os(
+                format!("Failed to waitid on pidfd for {}", pid),
+                nix::Error::Sys(errno),
+            ));
+        }
+
+        return Ok(siginfo_to_exit_status(&info));
+    }
+}
+
+/// Map the `si_code`/`si_status` pair filled in by `waitid(2)` to our
+/// `ExitStatus`. Only `CLD_EXITED`, `CLD_KILLED` and `CLD_DUMPED` are possible
+/// here since we only ever wait with `WEXITED`.
+#[cfg(target_os = "linux")]
+fn siginfo_to_exit_status(info: &libc::siginfo_t) -> ExitStatus {
+    // SAFETY: si_status is valid for any si_code returned by a WEXITED wait.
+    let status = unsafe { info.si_status() };
+    exit_status_from_siginfo(info.si_code, status)
+}
+
+/// The pure, testable half of `siginfo_to_exit_status`: turns the `si_code`/
+/// `si_status` pair already read out of a `siginfo_t` into an `ExitStatus`.
+#[cfg(target_os = "linux")]
+fn exit_status_from_siginfo(code: libc::c_int, status: libc::c_int) -> ExitStatus {
+    match code {
+        libc::CLD_EXITED => ExitStatus::Exit(status),
+        libc::CLD_KILLED => ExitStatus::Signaled {
+            signal: signal::Signal::try_from(status).unwrap_or(signal::Signal::SIGKILL),
+            core_dumped: false,
+        },
+        libc::CLD_DUMPED => ExitStatus::Signaled {
+            signal: signal::Signal::try_from(status).unwrap_or(signal::Signal::SIGKILL),
+            core_dumped: true,
+        },
+        code => unreachable!("Unexpected si_code {} from a WEXITED waitid", code),
+    }
+}
+
+/// Fallback used on non-Linux targets, and on Linux whenever `pidfd_open` fails
+/// (e.g. `ENOSYS` on older kernels) or reaping through the pidfd fails: park a
+/// dedicated thread in a blocking `waitpid(2)` loop until the child exits.
+async fn waitpid_blocking(pid: u32) -> ExitStatus {
     task::spawn_blocking(move || {
         let pid = unistd::Pid::from_raw(pid as i32);
-        let status = loop {
+        loop {
             let result = wait::waitpid(Some(pid), None);
             debug!("Result of wait_pid is {:?}", result);
 
             match result {
-                // The process exited normally (as with exit() or returning from main) with the given exit code.
-                // This case matches the C macro WIFEXITED(status); the second field is WEXITSTATUS(status).
-                Ok(WaitStatus::Exited(_pid, code)) => break ExitStatus::Exit(code),
-
-                // The process was killed by the given signal.
-                // The third field indicates whether the signal generated a core dump. This case matches the C macro WIFSIGNALED(status); the last two fields correspond to WTERMSIG(status) and WCOREDUMP(status).
-                Ok(WaitStatus::Signaled(_pid, signal, _dump)) => {
-                    break ExitStatus::Signaled(signal);
-                }
-
-                // The process is alive, but was stopped by the given signal.
-                // This is only reported if WaitPidFlag::WUNTRACED was passed. This case matches the C macro WIFSTOPPED(status); the second field is WSTOPSIG(status).
-                Ok(WaitStatus::Stopped(_pid, _signal)) => continue,
-
-                // The traced process was stopped by a PTRACE_EVENT_* event.
-                // See nix::sys::ptrace and ptrace(2) for more information. All currently-defined events use SIGTRAP as the signal; the third field is the PTRACE_EVENT_* value of the event.
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                Ok(WaitStatus::PtraceEvent(_pid, _signal, _)) => continue,
-
-                // The traced process was stopped by execution of a system call, and PTRACE_O_TRACESYSGOOD is in effect.
-                // See ptrace(2) for more information.
-                #[cfg(any(target_os = "linux", target_os = "android"))]
-                Ok(WaitStatus::PtraceSyscall(_pid)) => continue,
-
-                // The process was previously stopped but has resumed execution after receiving a SIGCONT signal.
-                // This is only reported if WaitPidFlag::WCONTINUED was passed. This case matches the C macro WIFCONTINUED(status).
-                Ok(WaitStatus::Continued(_pid)) => continue,
-
-                // There are currently no state changes to report in any awaited child process.
-                // This is only returned if WaitPidFlag::WNOHANG was used (otherwise wait() or waitpid() would block until there was something to report).
-                Ok(WaitStatus::StillAlive) => continue,
+                Ok(status) => match exit_status(status) {
+                    Some(status) => break status,
+                    None => continue,
+                },
                 // Retry the waitpid call if waitpid fails with EINTR
                 Err(e) if e == nix::Error::Sys(nix::errno::Errno::EINTR) => continue,
                 Err(e) => panic!("Failed to waitpid on {}: {}", pid, e),
             }
-        };
+        }
+    })
+    .await
+    .expect("waitpid thread panicked")
+}
 
-        // Send notification to exit handle
-        exit_handle.blocking_send(status.clone()).ok();
+/// Ask the process to terminate and wait for it to do so, escalating to
+/// `SIGKILL` if it has not exited within `grace`.
+///
+/// `pid` is never reaped here: the `waitpid` task already spawned for this
+/// container's lifetime is the sole reaper, so `stop` only raises signals and
+/// waits for that task's notification on `exit_handle_wait` (the receiving
+/// half of the `ExitHandleSignal` passed to the matching `waitpid` call).
+/// This is what keeps a graceful stop from racing `waitpid` to reap the same
+/// pid, which would otherwise see the loser get `ECHILD`.
+pub(crate) async fn stop(
+    name: &str,
+    pid: u32,
+    grace: Duration,
+    exit_handle_wait: &mut ExitHandleWait,
+) -> Result<ExitStatus, Error> {
+    let nix_pid = unistd::Pid::from_raw(pid as i32);
+
+    signal::kill(nix_pid, signal::Signal::SIGTERM)
+        .map_err(|e| Error::Os(format!("Failed to send SIGTERM to {}", pid), e))?;
+
+    let status = match time::timeout(grace, exit_handle_wait.recv()).await {
+        Ok(status) => status,
+        Err(_) => {
+            debug!(
+                "{} did not exit within {:?} of SIGTERM, sending SIGKILL",
+                name, grace
+            );
+            signal::kill(nix_pid, signal::Signal::SIGKILL)
+                .map_err(|e| Error::Os(format!("Failed to send SIGKILL to {}", pid), e))?;
+            exit_handle_wait.recv().await
+        }
+    };
+
+    status.ok_or(Error::Stop)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Send notification to main loop
-        event_handle
-            .blocking_send(Event::Exit(name.to_string(), status))
-            .expect("Internal channel error on main event handle");
-    });
+    #[test]
+    fn exit_status_maps_exited() {
+        let status = WaitStatus::Exited(unistd::Pid::from_raw(1), 42);
+        assert!(matches!(exit_status(status), Some(ExitStatus::Exit(42))));
+    }
+
+    #[test]
+    fn exit_status_maps_signaled() {
+        let status = WaitStatus::Signaled(unistd::Pid::from_raw(1), signal::Signal::SIGSEGV, true);
+        assert!(matches!(
+            exit_status(status),
+            Some(ExitStatus::Signaled {
+                signal: signal::Signal::SIGSEGV,
+                core_dumped: true,
+            })
+        ));
+    }
+
+    #[test]
+    fn exit_status_keeps_polling_on_transient_states() {
+        let status = WaitStatus::StillAlive;
+        assert!(exit_status(status).is_none());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn exit_status_from_siginfo_maps_exited() {
+        assert!(matches!(
+            exit_status_from_siginfo(libc::CLD_EXITED, 0),
+            ExitStatus::Exit(0)
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn exit_status_from_siginfo_maps_killed() {
+        assert!(matches!(
+            exit_status_from_siginfo(libc::CLD_KILLED, signal::Signal::SIGTERM as i32),
+            ExitStatus::Signaled {
+                signal: signal::Signal::SIGTERM,
+                core_dumped: false,
+            }
+        ));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn exit_status_from_siginfo_maps_dumped() {
+        assert!(matches!(
+            exit_status_from_siginfo(libc::CLD_DUMPED, signal::Signal::SIGSEGV as i32),
+            ExitStatus::Signaled {
+                signal: signal::Signal::SIGSEGV,
+                core_dumped: true,
+            }
+        ));
+    }
 }
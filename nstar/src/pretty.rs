@@ -20,7 +20,9 @@ use std::{collections::HashMap, io};
 use tokio::time;
 
 pub(crate) fn notification<W: io::Write>(mut w: W, notification: &Notification) {
-    // TODO
+    // Notification (api::model, outside this checkout) doesn't expose the
+    // process::ExitStatus it presumably wraps, so there's nothing here to
+    // call ExitStatus::Display on; stays on {:?} until it does.
     let msg = format!("📣  {:?}", notification);
     writeln!(w, "{}", msg).ok();
 }
@@ -36,6 +38,8 @@ pub(crate) fn containers<W: io::Write>(mut w: W, containers: &[Container]) -> Re
         Cell::new("PID").with_style(Attr::Bold),
         Cell::new("Uptime").with_style(Attr::Bold),
     ]));
+    // No "Exit" column: Container::process only carries pid/uptime, it
+    // doesn't expose the terminated container's process::ExitStatus.
     for container in containers
         .iter()
         .sorted_by_key(|c| &c.manifest.name) // Sort by name